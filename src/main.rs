@@ -1,41 +1,193 @@
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::string::FromUtf8Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use axum::extract::{Extension, Path};
+use axum::extract::{Extension, Path, Query};
 use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{AddExtensionLayer, Router, body};
+use atom_syndication::{Entry, Feed, Link};
 use bb8::{Pool, RunError};
+use chrono::{TimeZone, Utc};
 use bb8_redis::RedisConnectionManager;
 use clap::Parser;
+use futures::StreamExt;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use redis::{AsyncCommands, RedisError};
+use redis::{AsyncCommands, RedisError, Script};
+use serde::Deserialize;
 use thiserror::Error;
+use tokio_stream::wrappers::ReceiverStream;
 
 const EXPIRY_SECS: usize = 60 * 30;
 
-#[derive(Parser)]
+/// Pub/sub channel on which every newly created paste key is announced.
+const EVENTS_CHANNEL: &str = "pasta:events";
+
+/// Sorted set indexing public paste keys by creation timestamp (feed ordering).
+const RECENT_KEY: &str = "pasta:recent";
+
+/// Parallel index scored by expiry instant, used purely to prune expired keys
+/// from [`RECENT_KEY`] without disturbing its creation-time ordering.
+const RECENT_EXP_KEY: &str = "pasta:recent:expiry";
+
+/// Most entries the feed will surface.
+const FEED_LIMIT: isize = 50;
+
+const KEY_LEN: usize = 8;
+const ALLOC_ATTEMPTS: usize = 5;
+const MAX_KEY_LEN: usize = 16;
+
+/// Upper bound, in seconds, on a caller-requested paste TTL. Must be non-zero.
+#[derive(Clone, Copy)]
+struct MaxTtl(usize);
+
+impl FromStr for MaxTtl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>().map_err(|e| e.to_string())? {
+            0 => Err("max ttl must be greater than zero".to_string()),
+            secs => Ok(MaxTtl(secs)),
+        }
+    }
+}
+
+/// Maximum number of connections held open by the bb8 pool. Must be non-zero.
+#[derive(Clone, Copy)]
+struct PoolSize(u32);
+
+impl FromStr for PoolSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u32>().map_err(|e| e.to_string())? {
+            0 => Err("pool size must be greater than zero".to_string()),
+            size => Ok(PoolSize(size)),
+        }
+    }
+}
+
+/// How long a pool checkout waits for a connection before timing out, in
+/// seconds. Must be non-zero.
+#[derive(Clone, Copy)]
+struct ConnectTimeout(Duration);
+
+impl FromStr for ConnectTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u64>().map_err(|e| e.to_string())? {
+            0 => Err("connection timeout must be greater than zero".to_string()),
+            secs => Ok(ConnectTimeout(Duration::from_secs(secs))),
+        }
+    }
+}
+
+#[derive(Parser, Clone)]
 struct Args {
     #[clap(short, long, default_value = "redis://127.0.0.1:6379", env = "REDIS_URL")]
     redis_url: String,
 
     #[clap(short, long, default_value = "0.0.0.0:3000", env = "BIND_ADDR")]
     bind_addr: SocketAddr,
+
+    #[clap(long, default_value = "86400", env = "MAX_TTL_SECS")]
+    max_ttl: MaxTtl,
+
+    #[clap(long, default_value = "16", env = "POOL_MAX_SIZE")]
+    pool_max_size: PoolSize,
+
+    #[clap(long, default_value = "5", env = "POOL_CONNECTION_TIMEOUT")]
+    pool_connection_timeout: ConnectTimeout,
+
+    #[clap(long, default_value = "5", env = "REDIS_CONNECT_RETRIES")]
+    redis_connect_retries: u32,
+}
+
+/// Retention mode for a stored paste. `Burn` pastes are deleted on first read
+/// (the historical behaviour); `Keep` pastes survive until their TTL lapses.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Burn,
+    Keep,
+}
+
+impl Mode {
+    /// One-byte tag prefixed to the stored value so `get_paste` can strip it
+    /// back off when returning the body.
+    fn tag(self) -> char {
+        match self {
+            Mode::Burn => 'B',
+            Mode::Keep => 'K',
+        }
+    }
+}
+
+/// Whether a paste is advertised in the public feed. Private pastes (the
+/// default) leave no trace in the recent index.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Visibility {
+    Public,
+    Private,
+}
+
+/// Cached copies of the Lua scripts loaded once at startup and shared across
+/// requests via an [`Extension`].
+#[derive(Clone)]
+struct Scripts {
+    alloc: Script,
+    get: Script,
+}
+
+#[derive(Deserialize)]
+struct CreateParams {
+    ttl: Option<usize>,
+    mode: Option<Mode>,
+    visibility: Option<Visibility>,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let manager = RedisConnectionManager::new(args.redis_url).unwrap();
-    let pool = Pool::builder().build(manager).await.unwrap();
+    let manager = RedisConnectionManager::new(args.redis_url.clone())
+        .expect("invalid redis url");
+    let pool = Pool::builder()
+        .max_size(args.pool_max_size.0)
+        .connection_timeout(args.pool_connection_timeout.0)
+        .build(manager)
+        .await
+        .expect("failed to build redis pool");
+
+    connect_with_backoff(&pool, args.redis_connect_retries)
+        .await
+        .expect("could not reach redis");
+
+    // Dedicated client for subscribing connections, which can't be returned to
+    // the bb8 pool's command rotation once they enter subscribe mode.
+    let client = redis::Client::open(args.redis_url.clone())
+        .expect("invalid redis url");
+
+    let scripts = Scripts {
+        alloc: Script::new(include_str!("set_nx.lua")),
+        get: Script::new(include_str!("get_paste.lua")),
+    };
 
     let app: Router<_> = Router::new()
         .route("/paste/:key", get(get_paste))
         .route("/paste", post(create_paste))
-        .layer(AddExtensionLayer::new(pool));
+        .route("/stream", get(stream))
+        .route("/feed.xml", get(feed))
+        .layer(AddExtensionLayer::new(pool))
+        .layer(AddExtensionLayer::new(client))
+        .layer(AddExtensionLayer::new(scripts))
+        .layer(AddExtensionLayer::new(args.clone()));
 
     axum::Server::bind(&args.bind_addr)
         .serve(app.into_make_service())
@@ -43,6 +195,29 @@ async fn main() {
         .unwrap();
 }
 
+/// Probe Redis on startup, retrying with exponential backoff so that a Redis
+/// container that isn't up yet delays — rather than kills — the service.
+async fn connect_with_backoff(
+    pool: &Pool<RedisConnectionManager>,
+    retries: u32,
+) -> Result<(), PastaError> {
+    let mut backoff = Duration::from_millis(250);
+    for attempt in 1..=retries {
+        match pool.get().await {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                eprintln!("redis connection attempt {attempt}/{retries} failed: {e}");
+                if attempt < retries {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            },
+        }
+    }
+
+    Err(PastaError::ConnectionTimeout)
+}
+
 #[derive(Debug, Error)]
 pub enum PastaError {
     #[error("paste not found: {0}")]
@@ -53,6 +228,10 @@ pub enum PastaError {
     ConnectionTimeout,
     #[error("string decode error")]
     PasteDecodeError(#[from] FromUtf8Error),
+    #[error("exhausted attempts allocating a unique key")]
+    KeyAllocationExhausted,
+    #[error("requested ttl is zero or exceeds the configured maximum")]
+    InvalidTtl,
 }
 
 impl From<RunError<RedisError>> for PastaError {
@@ -87,6 +266,16 @@ impl IntoResponse for PastaError {
 
                 (StatusCode::BAD_REQUEST, body::boxed(body::Empty::new()))
             },
+            PastaError::KeyAllocationExhausted => {
+                eprintln!("exhausted attempts allocating a unique key");
+
+                (StatusCode::INTERNAL_SERVER_ERROR, body::boxed(body::Empty::new()))
+            },
+            PastaError::InvalidTtl => {
+                let body = body::boxed(body::Full::from("requested ttl is zero or exceeds maximum"));
+
+                (StatusCode::BAD_REQUEST, body)
+            },
         };
 
         Response::builder()
@@ -98,31 +287,229 @@ impl IntoResponse for PastaError {
 async fn get_paste(
     Path(key): Path<String>,
     Extension(pool): Extension<Pool<RedisConnectionManager>>,
+    Extension(scripts): Extension<Scripts>,
 ) -> Result<String, PastaError> {
     let mut conn = pool.get().await?;
 
+    // The fetch script atomically reads the value and, for burn pastes, deletes
+    // it in the same step — preserving the baseline's `GETDEL` semantics so a
+    // burn secret can never be handed to two concurrent readers.
     let redis_key = format!("pasta:{key}");
-    let value: Option<String> = redis::cmd("GETDEL")
-        .arg(redis_key)
+    let value: Option<String> = scripts
+        .get
+        .key(redis_key)
+        .invoke_async(&mut *conn)
+        .await?;
+    let mut stored = value.ok_or_else(|| PastaError::NotFound(key.clone()))?;
+
+    // Strip the leading retention tag before returning the body.
+    let tag = stored.chars().next().unwrap_or('B');
+    stored.drain(..tag.len_utf8());
+    Ok(stored)
+}
+
+async fn stream(
+    Extension(client): Extension<redis::Client>,
+) -> Result<Sse<ReceiverStream<Result<Event, std::convert::Infallible>>>, PastaError> {
+    let conn = client.get_async_connection().await?;
+    let mut pubsub = conn.into_pubsub();
+    pubsub.subscribe(EVENTS_CHANNEL).await?;
+
+    // Pump messages out of the subscribed connection (which lives in the spawned
+    // task) and into an SSE stream over the channel receiver.
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let key: String = match msg.get_payload() {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if tx.send(Ok(Event::default().data(key))).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+async fn feed(
+    Extension(pool): Extension<Pool<RedisConnectionManager>>,
+) -> Result<Response, PastaError> {
+    let mut conn = pool.get().await?;
+
+    // Drop keys that have expired using the expiry index, removing them from
+    // both indices, then list the remaining members newest-first by creation.
+    let now = now_secs();
+    let expired: Vec<String> = conn.zrangebyscore(RECENT_EXP_KEY, 0u64, now).await?;
+    if !expired.is_empty() {
+        let _: () = conn.zrem(RECENT_KEY, &expired).await?;
+        let _: () = conn.zrem(RECENT_EXP_KEY, &expired).await?;
+    }
+
+    let recent: Vec<String> = redis::cmd("ZREVRANGEBYSCORE")
+        .arg(RECENT_KEY)
+        .arg("+inf")
+        .arg("-inf")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(FEED_LIMIT)
         .query_async(&mut *conn)
         .await?;
 
-    value.ok_or(PastaError::NotFound(key))
+    let mut entries = Vec::with_capacity(recent.len());
+    for key in recent {
+        let meta_key = format!("pasta:meta:{key}");
+        let title: Option<String> = conn.hget(&meta_key, "title").await?;
+        let created: Option<i64> = conn.hget(&meta_key, "created").await?;
+        let href = format!("/paste/{key}");
+
+        let mut link = Link::default();
+        link.set_href(&href);
+
+        let updated = Utc
+            .timestamp_opt(created.unwrap_or(0), 0)
+            .single()
+            .unwrap_or_else(Utc::now)
+            .fixed_offset();
+
+        let mut entry = Entry::default();
+        entry.set_id(&href);
+        entry.set_title(title.filter(|t| !t.is_empty()).unwrap_or_else(|| key.clone()));
+        entry.set_links(vec![link]);
+        entry.set_updated(updated);
+        entries.push(entry);
+    }
+
+    let mut feed = Feed::default();
+    feed.set_title("Recent public pastes");
+    feed.set_id("/feed.xml");
+    feed.set_updated(Utc::now().fixed_offset());
+    feed.set_entries(entries);
+
+    let response = Response::builder()
+        .header("content-type", "application/atom+xml")
+        .body(body::boxed(body::Full::from(feed.to_string())))
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Resolve the effective TTL for a paste, falling back to [`EXPIRY_SECS`] and
+/// rejecting anything above the configured maximum.
+fn resolve_ttl(requested: Option<usize>, max: usize) -> Result<usize, PastaError> {
+    match requested {
+        Some(ttl) if ttl == 0 || ttl > max => Err(PastaError::InvalidTtl),
+        Some(ttl) => Ok(ttl),
+        None => Ok(EXPIRY_SECS),
+    }
+}
+
+fn sample_key(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
 }
 
 async fn create_paste(
-    paste: String,
+    Query(params): Query<CreateParams>,
     Extension(pool): Extension<Pool<RedisConnectionManager>>,
+    Extension(scripts): Extension<Scripts>,
+    Extension(args): Extension<Args>,
+    paste: String,
 ) -> Result<String, PastaError> {
+    let ttl = resolve_ttl(params.ttl, args.max_ttl.0)?;
+    let mode = params.mode.unwrap_or(Mode::Burn);
+    let public = params.visibility == Some(Visibility::Public);
+    let stored = format!("{}{paste}", mode.tag());
+    let title = paste.lines().next().unwrap_or_default().chars().take(80).collect::<String>();
+
     let mut conn = pool.get().await?;
 
-    let key = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect::<String>();
-    let redis_key = format!("pasta:{key}");
-    let _: () = conn.set_ex(redis_key, &paste, EXPIRY_SECS).await?;
+    // Keep sampling keys until the allocation script claims one that wasn't
+    // already taken; widen the key space by a character once a run of attempts
+    // at the current length all collide.
+    for len in KEY_LEN..=MAX_KEY_LEN {
+        for _ in 0..ALLOC_ATTEMPTS {
+            let key = sample_key(len);
+            let redis_key = format!("pasta:{key}");
+            let claimed: i64 = scripts
+                .alloc
+                .key(redis_key)
+                .arg(&stored)
+                .arg(ttl)
+                .invoke_async(&mut *conn)
+                .await?;
+
+            if claimed == 1 {
+                // Only public pastes are advertised: the key *is* the read
+                // capability, so announcing a private burn paste's key would let
+                // any stream subscriber fetch-and-destroy it before the intended
+                // recipient. Publish the key (never the body) for public pastes.
+                if public {
+                    let _: () = conn.publish(EVENTS_CHANNEL, &key).await?;
+
+                    // Order the feed by creation time, but keep a parallel
+                    // expiry-scored index so the feed can prune members exactly
+                    // when their underlying keys lapse, honouring per-paste TTLs.
+                    let now = now_secs();
+                    let expires_at = now.saturating_add(ttl as u64);
+                    let meta_key = format!("pasta:meta:{key}");
+                    let _: () = conn.zadd(RECENT_KEY, &key, now).await?;
+                    let _: () = conn.zadd(RECENT_EXP_KEY, &key, expires_at).await?;
+                    let _: () = conn
+                        .hset_multiple(&meta_key, &[("title", title.as_str()), ("created", &now.to_string())])
+                        .await?;
+                    let _: () = conn.expire(&meta_key, ttl).await?;
+                }
 
-    Ok(key)
+                return Ok(key);
+            }
+        }
+    }
+
+    Err(PastaError::KeyAllocationExhausted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_defaults_when_absent() {
+        assert_eq!(resolve_ttl(None, 100).unwrap(), EXPIRY_SECS);
+    }
+
+    #[test]
+    fn ttl_within_bounds_is_accepted() {
+        assert_eq!(resolve_ttl(Some(42), 100).unwrap(), 42);
+        assert_eq!(resolve_ttl(Some(100), 100).unwrap(), 100);
+    }
+
+    #[test]
+    fn ttl_above_maximum_is_rejected() {
+        assert!(matches!(
+            resolve_ttl(Some(101), 100),
+            Err(PastaError::InvalidTtl)
+        ));
+    }
+
+    #[test]
+    fn zero_ttl_is_rejected() {
+        // `EX 0` is invalid in Redis, so reject it up front as a clean 400.
+        assert!(matches!(
+            resolve_ttl(Some(0), 100),
+            Err(PastaError::InvalidTtl)
+        ));
+    }
 }